@@ -0,0 +1,141 @@
+//! Persisted boot-state page: tracks which of the active/staging/previous
+//! flash regions are in play across a firmware update, so a reset at any
+//! point during a swap resumes instead of bricking the device.
+//!
+//! Modeled on the trial-boot/rollback scheme used by embassy-boot: a new
+//! image is never written directly over the running one. It lands in the
+//! staging slot, and only gets copied into the active slot - with the
+//! previous active image backed up first - once its CRC has been
+//! verified. The freshly-swapped app then runs on probation (`Trial`)
+//! until it confirms itself or the boot-attempt counter runs out, in
+//! which case the backed-up image is copied back in.
+
+use defmt::*;
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::peripherals::FLASH;
+
+use crate::slot::round_up_to_sector;
+use crate::{FLASH_SIZE, METADATA_SIZE};
+
+const MAGIC_BOOT: &[u8; 4] = b"BOOT";
+
+/// Number of trial boots the application gets to confirm itself (see
+/// [`crate::watchdog::MAGIC_TRIAL_CONFIRMED`]) before the bootloader
+/// assumes it is bad and rolls back.
+pub const MAX_TRIAL_BOOTS: u8 = 3;
+
+/// Lifecycle of the active slot, persisted across resets.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum BootState {
+    /// Active slot has been confirmed good by the application.
+    BootOk = 0,
+    /// A swap into the active slot is underway or still needs to start.
+    SwapPending = 1,
+    /// The active slot was just swapped in and is running on probation.
+    Trial = 2,
+}
+
+impl BootState {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::BootOk),
+            1 => Some(Self::SwapPending),
+            2 => Some(Self::Trial),
+            _ => None,
+        }
+    }
+}
+
+/// In-memory view of the persisted boot-state page.
+#[derive(Clone, Copy, Format)]
+pub struct BootStatePage {
+    pub state: BootState,
+    /// True while a `SwapPending` copy is restoring `previous` back into
+    /// `active` (rollback) rather than bringing `staging` in (update).
+    pub rollback: bool,
+    /// Set once `active` has been backed up into `previous` for the
+    /// in-progress update, so a resumed copy doesn't redo it.
+    pub backup_done: bool,
+    /// Boots spent in `Trial` so far.
+    pub boot_count: u8,
+    /// Bytes already copied into `active` for the in-progress swap, so a
+    /// reset mid-copy resumes instead of starting over.
+    pub copy_progress: u32,
+}
+
+impl Default for BootStatePage {
+    fn default() -> Self {
+        Self {
+            state: BootState::BootOk,
+            rollback: false,
+            backup_done: false,
+            boot_count: 0,
+            copy_progress: 0,
+        }
+    }
+}
+
+impl BootStatePage {
+    fn to_bytes(self) -> [u8; METADATA_SIZE as usize] {
+        let mut buf = [0u8; METADATA_SIZE as usize];
+        buf[0..4].copy_from_slice(MAGIC_BOOT);
+        buf[4] = self.state as u8;
+        buf[5] = self.rollback as u8;
+        buf[6] = self.backup_done as u8;
+        buf[7] = self.boot_count;
+        buf[8..12].copy_from_slice(&self.copy_progress.to_le_bytes());
+
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let digest = crc.checksum(&buf[0..12]);
+        buf[12..16].copy_from_slice(&digest.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; METADATA_SIZE as usize]) -> Option<Self> {
+        if &buf[0..4] != MAGIC_BOOT {
+            return None;
+        }
+        let expected = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        if crc.checksum(&buf[0..12]) != expected {
+            return None;
+        }
+        Some(Self {
+            state: BootState::from_u8(buf[4])?,
+            rollback: buf[5] != 0,
+            backup_done: buf[6] != 0,
+            boot_count: buf[7],
+            copy_progress: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+/// Loads the boot-state page, falling back to a fresh `BootOk` page if it
+/// is erased or CRC-invalid (e.g. first boot after flashing).
+pub fn load_state(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, offset: u32) -> BootStatePage {
+    let mut buf = [0u8; METADATA_SIZE as usize];
+    if flash.read(offset, &mut buf).is_err() {
+        warn!("Boot-state page unreadable, assuming BOOT_OK");
+        return BootStatePage::default();
+    }
+    BootStatePage::from_bytes(&buf).unwrap_or_else(|| {
+        info!("No valid boot-state page found, initializing to BOOT_OK");
+        BootStatePage::default()
+    })
+}
+
+/// Persists the boot-state page, erasing it first since flash can only be
+/// written to a page that reads as all-ones. The erase is rounded up to a
+/// full sector since `METADATA_SIZE` (one flash *page*) is smaller than
+/// the flash's erase granularity.
+pub fn store_state(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, offset: u32, page: BootStatePage) {
+    if let Err(e) = flash.erase(offset, offset + round_up_to_sector(METADATA_SIZE)) {
+        error!("Boot-state erase failed: {:?}", e);
+        return;
+    }
+    if let Err(e) = flash.write(offset, &page.to_bytes()) {
+        error!("Boot-state write failed: {:?}", e);
+    }
+}
+
@@ -0,0 +1,430 @@
+//! Framed, sequence-numbered DFU protocol.
+//!
+//! Each frame on the wire is `[0xAA][type:u8][seq:u16][len:u16][payload][crc16]`,
+//! with the CRC16 computed over everything between the magic byte and the
+//! CRC itself. The bootloader ACKs a frame once its CRC16 checks out *and*
+//! its sequence number is the one we're expecting, and NAKs otherwise (bad
+//! CRC, or an out-of-order/duplicate sequence number) - so the host can
+//! always retransmit whatever the last ACK/NAK told it to, rather than
+//! re-sending the whole image after one corrupted chunk.
+//!
+//! A `Hello` frame lets the host discover the bootloader version, max
+//! frame size and flash granularity before it starts sending data, and a
+//! `Resume` frame lets it ask how much of the staging slot is already
+//! committed (from a previous transfer interrupted by a reset) so it can
+//! fast-forward instead of starting over. To actually continue such a
+//! transfer the host still sends `Start`, but with its leading
+//! `resume` byte set - that skips the staging-slot erase and seeds
+//! `received` from what's already committed, instead of treating the
+//! transfer as brand new.
+//!
+//! `EnterRecovery` diverts a transfer away from the staging slot and into
+//! [`crate::recovery`]'s RAM buffer instead, for reprogramming the
+//! bootloader region itself; see that module for the safety checks gating
+//! it.
+//!
+//! With the `signed-image` feature enabled, `Start` carries a hash and
+//! detached signature in addition to length and CRC32, and `End` refuses
+//! to stage the image - erasing it instead - unless the signature checks
+//! out against [`crate::signature::PUBLIC_KEY`].
+
+use defmt::*;
+use embassy_rp::flash::{Async as FlashAsync, Flash};
+use embassy_rp::peripherals::{FLASH, UART0};
+use embassy_rp::uart::{Async as UartAsync, Uart};
+use embassy_rp::watchdog::Watchdog;
+
+use crate::boot_state::{self, BootState, BootStatePage};
+use crate::recovery;
+use crate::slot::Slot;
+use crate::watchdog as wdg;
+use crate::{FLASH_SIZE, MAGIC_APPS, METADATA_SIZE, SLOT_SIZE, STAGING_OFFSET, STATE_OFFSET};
+#[cfg(feature = "signed-image")]
+use crate::signature::{HASH_SIZE, SIGNATURE_SIZE};
+#[cfg(feature = "signed-image")]
+use crate::slot::{HASH_OFFSET, SIGNATURE_OFFSET};
+
+const MAGIC: u8 = 0xAA;
+const MAX_PAYLOAD: usize = 1024;
+
+/// Bumped whenever the frame format or command set changes.
+const PROTOCOL_VERSION: u8 = 1;
+const PAGE_SIZE: u16 = 256;
+const ERASE_GRANULARITY: u16 = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+enum FrameType {
+    Hello = 0,
+    HelloAck = 1,
+    Resume = 2,
+    ResumeAck = 3,
+    Start = 4,
+    Data = 5,
+    End = 6,
+    Ack = 7,
+    Nak = 8,
+    Error = 9,
+    /// Host wants to reprogram the bootloader region itself instead of the
+    /// staging slot; payload is `[total_len:u32][total_crc:u32][confirm_token:u32]`.
+    EnterRecovery = 10,
+}
+
+impl FrameType {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Hello,
+            1 => Self::HelloAck,
+            2 => Self::Resume,
+            3 => Self::ResumeAck,
+            4 => Self::Start,
+            5 => Self::Data,
+            6 => Self::End,
+            7 => Self::Ack,
+            8 => Self::Nak,
+            9 => Self::Error,
+            10 => Self::EnterRecovery,
+            _ => return None,
+        })
+    }
+}
+
+struct Frame {
+    ty: FrameType,
+    seq: u16,
+    len: u16,
+    payload: [u8; MAX_PAYLOAD],
+}
+
+fn crc16(head: &[u8], payload: &[u8]) -> u16 {
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+    let mut digest = crc.digest();
+    digest.update(head);
+    digest.update(payload);
+    digest.finalize()
+}
+
+async fn read_frame(uart: &mut Uart<'_, UART0, UartAsync>) -> Option<Frame> {
+    // Resync to the magic byte so a glitch on the line can't desync us
+    // from frame boundaries forever.
+    let mut b = [0u8; 1];
+    loop {
+        uart.read(&mut b).await.ok()?;
+        if b[0] == MAGIC {
+            break;
+        }
+    }
+
+    let mut head = [0u8; 5]; // [type(1) | seq(2) | len(2)]
+    uart.read(&mut head).await.ok()?;
+    let ty = FrameType::from_u8(head[0])?;
+    let seq = u16::from_le_bytes([head[1], head[2]]);
+    let len = u16::from_le_bytes([head[3], head[4]]);
+    if len as usize > MAX_PAYLOAD {
+        return None;
+    }
+
+    let mut payload = [0u8; MAX_PAYLOAD];
+    if len > 0 {
+        uart.read(&mut payload[..len as usize]).await.ok()?;
+    }
+
+    let mut crc_buf = [0u8; 2];
+    uart.read(&mut crc_buf).await.ok()?;
+    let received_crc = u16::from_le_bytes(crc_buf);
+
+    if crc16(&head, &payload[..len as usize]) != received_crc {
+        debug!("Frame seq={} failed CRC16", seq);
+        return None;
+    }
+
+    Some(Frame { ty, seq, len, payload })
+}
+
+async fn write_frame(uart: &mut Uart<'_, UART0, UartAsync>, ty: FrameType, seq: u16, payload: &[u8]) {
+    let mut head = [0u8; 5];
+    head[0] = ty as u8;
+    head[1..3].copy_from_slice(&seq.to_le_bytes());
+    head[3..5].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    let crc_val = crc16(&head, payload);
+
+    let _ = uart.write(&[MAGIC]).await;
+    let _ = uart.write(&head).await;
+    let _ = uart.write(payload).await;
+    let _ = uart.write(&crc_val.to_le_bytes()).await;
+}
+
+async fn ack(uart: &mut Uart<'_, UART0, UartAsync>, seq: u16) {
+    write_frame(uart, FrameType::Ack, seq, &[]).await;
+}
+
+async fn nak(uart: &mut Uart<'_, UART0, UartAsync>, seq: u16) {
+    write_frame(uart, FrameType::Nak, seq, &[]).await;
+}
+
+/// Scans the staging slot's image region for the first erased (all-0xFF)
+/// page, which is a good-enough estimate of "bytes already committed" for
+/// resuming a transfer - any fully written page reads back as real data,
+/// never a blank page.
+fn scan_committed(flash: &mut Flash<FLASH, FlashAsync, FLASH_SIZE>) -> u32 {
+    let mut staging = Slot::new(flash, STAGING_OFFSET, SLOT_SIZE);
+    let mut buf = [0u8; PAGE_SIZE as usize];
+    let mut offset = 0u32;
+    while offset + buf.len() as u32 <= SLOT_SIZE - METADATA_SIZE {
+        if staging.read(METADATA_SIZE + offset, &mut buf).is_err() {
+            break;
+        }
+        if buf.iter().all(|&b| b == 0xFF) {
+            break;
+        }
+        offset += buf.len() as u32;
+    }
+    offset
+}
+
+/// Runs the framed DFU protocol until a transfer completes (in which case
+/// the device resets and this never returns) or a fatal error sends us
+/// back to the caller, which falls through to trying the app instead.
+///
+/// The watchdog isn't armed until the host actually says something - a
+/// human has to launch the flashing tool after the device enters update
+/// mode, and that can take far longer than any reasonable transfer
+/// timeout. Arming `DFU_TIMEOUT` up front would reboot us mid-idle-wait,
+/// which (since the DFU-entry scratch-register command is cleared on
+/// read) would silently turn a forced-DFU request back into a normal
+/// boot. Once the first frame (well-formed or not) has been read, the host is on the line
+/// and a stalled transfer (hung cable, crashed flashing tool) is exactly
+/// what the timeout should catch, so it gets armed then and fed once per
+/// loop iteration after that.
+pub async fn run(
+    uart: &mut Uart<'_, UART0, UartAsync>,
+    flash: &mut Flash<FLASH, FlashAsync, FLASH_SIZE>,
+    watchdog: &mut Watchdog,
+) {
+    let mut expected_seq: u16 = 0;
+    let mut total_len = 0u32;
+    let mut total_crc = 0u32;
+    let mut received = 0u32;
+    // `true` once the host has entered recovery mode: writes land in the
+    // RAM recovery buffer instead of the staging slot, and `End` reprograms
+    // the bootloader region instead of staging an app update.
+    let mut in_recovery = false;
+    #[cfg(feature = "signed-image")]
+    let mut total_hash = [0u8; HASH_SIZE];
+    #[cfg(feature = "signed-image")]
+    let mut total_sig = [0u8; SIGNATURE_SIZE];
+    let mut watchdog_armed = false;
+
+    loop {
+        if watchdog_armed {
+            wdg::feed(watchdog);
+        }
+        let frame = read_frame(uart).await;
+        if !watchdog_armed {
+            wdg::start(watchdog, wdg::DFU_TIMEOUT);
+            watchdog_armed = true;
+        }
+        let frame = match frame {
+            Some(f) => f,
+            None => {
+                nak(uart, expected_seq).await;
+                continue;
+            }
+        };
+
+        match frame.ty {
+            FrameType::Hello => {
+                let mut payload = [0u8; 7];
+                payload[0] = PROTOCOL_VERSION;
+                payload[1..3].copy_from_slice(&(MAX_PAYLOAD as u16).to_le_bytes());
+                payload[3..5].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+                payload[5..7].copy_from_slice(&ERASE_GRANULARITY.to_le_bytes());
+                write_frame(uart, FrameType::HelloAck, frame.seq, &payload).await;
+            }
+            FrameType::Resume => {
+                received = scan_committed(flash);
+                expected_seq = 0;
+                info!("Resume query: {} bytes already committed", received);
+                write_frame(uart, FrameType::ResumeAck, frame.seq, &received.to_le_bytes()).await;
+            }
+            FrameType::Start => {
+                // `[resume:u8][total_len:u32][total_crc:u32]`, plus hash/sig
+                // when built with `signed-image`.
+                #[cfg(not(feature = "signed-image"))]
+                const START_LEN: u16 = 9;
+                #[cfg(feature = "signed-image")]
+                const START_LEN: u16 = 9 + HASH_SIZE as u16 + SIGNATURE_SIZE as u16;
+                if frame.len != START_LEN {
+                    nak(uart, frame.seq).await;
+                    continue;
+                }
+                let resume = frame.payload[0] != 0;
+                total_len = u32::from_le_bytes(frame.payload[1..5].try_into().unwrap());
+                total_crc = u32::from_le_bytes(frame.payload[5..9].try_into().unwrap());
+                info!("Starting transfer: {} bytes, CRC32 0x{:x}, resume={}", total_len, total_crc, resume);
+                #[cfg(feature = "signed-image")]
+                {
+                    total_hash.copy_from_slice(&frame.payload[9..9 + HASH_SIZE]);
+                    total_sig.copy_from_slice(&frame.payload[9 + HASH_SIZE..9 + HASH_SIZE + SIGNATURE_SIZE]);
+                }
+
+                // A resumed transfer continues writing into whatever the
+                // staging slot already holds, so it must not erase (that
+                // would wipe the very progress being resumed) and must pick
+                // `received` back up from what's actually committed there,
+                // rather than the `0` a fresh transfer starts from.
+                if resume {
+                    received = scan_committed(flash);
+                    expected_seq = 0;
+                    in_recovery = false;
+                    info!("Resuming transfer at {} bytes", received);
+                    ack(uart, frame.seq).await;
+                } else {
+                    let total_erase =
+                        (total_len + METADATA_SIZE + (ERASE_GRANULARITY as u32 - 1)) & !(ERASE_GRANULARITY as u32 - 1);
+                    let mut staging = Slot::new(flash, STAGING_OFFSET, SLOT_SIZE);
+                    match staging.erase(total_erase) {
+                        Ok(()) => {
+                            received = 0;
+                            expected_seq = 0;
+                            in_recovery = false;
+                            ack(uart, frame.seq).await;
+                        }
+                        Err(e) => {
+                            error!("Erase failed: {:?}", e);
+                            nak(uart, frame.seq).await;
+                        }
+                    }
+                }
+            }
+            FrameType::EnterRecovery => {
+                if frame.len != 12 {
+                    nak(uart, frame.seq).await;
+                    continue;
+                }
+                let len = u32::from_le_bytes(frame.payload[0..4].try_into().unwrap());
+                let crc = u32::from_le_bytes(frame.payload[4..8].try_into().unwrap());
+                let token = u32::from_le_bytes(frame.payload[8..12].try_into().unwrap());
+                if token != recovery::CONFIRMATION_TOKEN || len as usize > recovery::MAX_BOOTLOADER_SIZE {
+                    warn!("Refusing recovery mode: bad confirmation token or oversized image");
+                    nak(uart, frame.seq).await;
+                    continue;
+                }
+                warn!("Entering bootloader recovery mode: {} bytes, CRC32 0x{:x}", len, crc);
+                total_len = len;
+                total_crc = crc;
+                received = 0;
+                expected_seq = 0;
+                in_recovery = true;
+                ack(uart, frame.seq).await;
+            }
+            FrameType::Data => {
+                if frame.seq != expected_seq {
+                    // Duplicate of an already-applied frame, or out of order
+                    // after a dropped one: write nothing and NAK with the
+                    // sequence number we expect next.
+                    nak(uart, expected_seq).await;
+                    continue;
+                }
+
+                if received + frame.len as u32 > total_len {
+                    error!("Data frame would overrun declared transfer length, rejecting");
+                    nak(uart, frame.seq).await;
+                    continue;
+                }
+
+                let write_ok = if in_recovery {
+                    let buf = recovery::buffer();
+                    let n = frame.len as usize;
+                    buf[received as usize..received as usize + n].copy_from_slice(&frame.payload[..n]);
+                    true
+                } else {
+                    let mut staging = Slot::new(flash, STAGING_OFFSET, SLOT_SIZE);
+                    staging.write(METADATA_SIZE + received, &frame.payload[..frame.len as usize]).is_ok()
+                };
+
+                if write_ok {
+                    received += frame.len as u32;
+                    expected_seq = expected_seq.wrapping_add(1);
+                    ack(uart, frame.seq).await;
+                } else {
+                    error!("Flash write failed for seq {}", frame.seq);
+                    nak(uart, frame.seq).await;
+                }
+            }
+            FrameType::End if in_recovery => {
+                if received != total_len {
+                    warn!("End requested with {}/{} bytes received", received, total_len);
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x01]).await;
+                    continue;
+                }
+
+                // commit() only ever returns on failure - once the image
+                // passes verification it resets the device itself from
+                // RAM-resident code instead of returning here, since by
+                // that point this function's own flash-resident code may
+                // have been overwritten. See recovery::commit's doc comment.
+                if recovery::commit(flash, &recovery::buffer()[..total_len as usize], total_crc).is_err() {
+                    error!("Recovery image rejected, bootloader region left untouched.");
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x02]).await;
+                    continue;
+                }
+            }
+            FrameType::End => {
+                if received != total_len {
+                    warn!("End requested with {}/{} bytes received", received, total_len);
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x01]).await;
+                    continue;
+                }
+
+                let mut staging = Slot::new(flash, STAGING_OFFSET, SLOT_SIZE);
+                if !staging.verify_crc(total_len, total_crc) {
+                    error!("CRC mismatch! Application might be corrupted.");
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x02]).await;
+                    return;
+                }
+
+                let mut metadata = [0u8; METADATA_SIZE as usize];
+                metadata[0..4].copy_from_slice(MAGIC_APPS);
+                metadata[4..8].copy_from_slice(&total_len.to_le_bytes());
+                metadata[8..12].copy_from_slice(&total_crc.to_le_bytes());
+                #[cfg(feature = "signed-image")]
+                {
+                    metadata[HASH_OFFSET as usize..HASH_OFFSET as usize + HASH_SIZE].copy_from_slice(&total_hash);
+                    metadata[SIGNATURE_OFFSET as usize..SIGNATURE_OFFSET as usize + SIGNATURE_SIZE]
+                        .copy_from_slice(&total_sig);
+                }
+                if staging.write(0, &metadata).is_err() {
+                    error!("Metadata write failed");
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x03]).await;
+                    return;
+                }
+
+                #[cfg(feature = "signed-image")]
+                if !staging.is_app_healthy() {
+                    error!("Signature verification failed! Erasing staging slot.");
+                    let total_erase =
+                        (total_len + METADATA_SIZE + (ERASE_GRANULARITY as u32 - 1)) & !(ERASE_GRANULARITY as u32 - 1);
+                    let _ = staging.erase(total_erase);
+                    write_frame(uart, FrameType::Error, frame.seq, &[0x04]).await;
+                    continue;
+                }
+
+                ack(uart, frame.seq).await;
+                info!("Staged! Marking swap pending and resetting...");
+                let mut state = BootStatePage::default();
+                state.state = BootState::SwapPending;
+                boot_state::store_state(flash, STATE_OFFSET, state);
+                // Wait a bit for the ACK to actually get sent.
+                for _ in 0..100000 {
+                    core::hint::spin_loop();
+                }
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+            FrameType::HelloAck | FrameType::ResumeAck | FrameType::Ack | FrameType::Nak | FrameType::Error => {
+                // Host-bound frame types; ignore if somehow looped back to us.
+                nak(uart, frame.seq).await;
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
 #![no_std]
 #![no_main]
 
+mod boot_state;
+mod dfu;
+mod recovery;
+#[cfg(feature = "signed-image")]
+mod signature;
+mod slot;
+mod watchdog;
+
+use boot_state::{BootState, BootStatePage};
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
@@ -8,9 +17,10 @@ use embassy_rp::flash::{Async, Flash};
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::{DMA_CH0, DMA_CH1, DMA_CH2, FLASH, UART0};
 use embassy_rp::uart::{Config as UartConfig, InterruptHandler as UartInterruptHandler, Uart};
+use embassy_rp::watchdog::Watchdog;
 use embassy_rp::{bind_interrupts, dma};
 use embassy_time::{Duration, Timer};
-use embedded_storage::nor_flash::NorFlash;
+use slot::Slot;
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
@@ -20,11 +30,28 @@ bind_interrupts!(struct Irqs {
                  dma::InterruptHandler<DMA_CH2>;
 });
 
-const APP_OFFSET: u32 = 64 * 1024; // 64KB
 const FLASH_BASE_ADDR: u32 = 0x1000_0000;
-const APP_BASE: u32 = FLASH_BASE_ADDR + APP_OFFSET;
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
 const METADATA_SIZE: u32 = 256; // One flash page
-const REAL_APP_BASE: u32 = APP_BASE + METADATA_SIZE;
+
+/// NOR flash erase granularity: `erase()` calls must be sector-aligned, so
+/// anything that erases a region smaller than this (the boot-state page,
+/// a slot backup/swap) has to round up to it first. See [`slot::round_up_to_sector`].
+const ERASE_GRANULARITY: u32 = 4096;
+
+// Flash layout (offsets from FLASH_BASE_ADDR):
+//   [0, APP_OFFSET)                     bootloader
+//   [APP_OFFSET, +SLOT_SIZE)            active slot   (metadata + app image, what we boot)
+//   [STAGING_OFFSET, +SLOT_SIZE)        staging slot  (DFU writes land here)
+//   [PREVIOUS_OFFSET, +SLOT_SIZE)       previous slot (backup of active, for rollback)
+//   [STATE_OFFSET, +METADATA_SIZE)      boot-state page
+const APP_OFFSET: u32 = 64 * 1024; // 64KB
+const SLOT_SIZE: u32 = 600 * 1024;
+const STAGING_OFFSET: u32 = APP_OFFSET + SLOT_SIZE;
+const PREVIOUS_OFFSET: u32 = STAGING_OFFSET + SLOT_SIZE;
+const STATE_OFFSET: u32 = PREVIOUS_OFFSET + SLOT_SIZE;
+
+const REAL_APP_BASE: u32 = FLASH_BASE_ADDR + APP_OFFSET + METADATA_SIZE;
 
 const MAGIC_APPS: &[u8; 4] = b"APPS";
 
@@ -42,16 +69,30 @@ async fn main(_spawner: Spawner) {
     led.set_high();
 
     // Flash driver setup
-    let mut flash: Flash<FLASH, Async, { 2 * 1024 * 1024 }> = Flash::new(p.FLASH, p.DMA_CH2, Irqs);
+    let mut flash: Flash<FLASH, Async, FLASH_SIZE> = Flash::new(p.FLASH, p.DMA_CH2, Irqs);
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+
+    // A boot-reason handoff left by the application: either "enter DFU
+    // without the prompt" or "the trial boot I'm running is good".
+    let boot_cmd = watchdog::take_command();
+    let watchdog_caused_reset = watchdog::caused_reset();
 
     let mut uart_buf = [0u8; 1];
 
+    // Resolve any pending swap/rollback left over from a previous update or
+    // a reset that interrupted one, before we even look at the active slot.
+    let boot_state = resolve_boot_state(&mut flash, watchdog_caused_reset, boot_cmd == watchdog::MAGIC_TRIAL_CONFIRMED);
+
     // Check application health (Magic + CRC32)
-    let app_healthy = unsafe { is_app_healthy(APP_BASE) };
-    let mut update_mode = !app_healthy;
+    let app_healthy = Slot::new(&mut flash, APP_OFFSET, SLOT_SIZE).is_app_healthy();
+    let mut update_mode = !app_healthy || boot_cmd == watchdog::MAGIC_ENTER_DFU;
 
     if update_mode {
-        warn!("Application is corrupted or missing! Entering Update Mode.");
+        if !app_healthy {
+            warn!("Application is corrupted or missing! Entering Update Mode.");
+        } else {
+            info!("Application requested DFU mode via scratch register.");
+        }
     } else {
         info!("Application healthy. Press 'u' for Update, or wait 3s to Jump...");
         let start_time = embassy_time::Instant::now();
@@ -87,77 +128,17 @@ async fn main(_spawner: Spawner) {
 
     if update_mode {
         led.set_low();
-        info!("DFU Mode: Wait for magic 0xAA...");
-        
-        loop {
-            if let Ok(_) = uart.read(&mut uart_buf).await {
-                if uart_buf[0] == 0xAA {
-                    break;
-                }
-            }
-        }
-
-        let mut header = [0u8; 8]; // [Length(4) | CRC32(4)]
-        if uart.read(&mut header).await.is_ok() {
-            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
-            let crc_val = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
-            info!("Receiving {} bytes, CRC32: 0x{:x}", len, crc_val);
-
-            // Erase app area + metadata
-            let total_erase = (len + METADATA_SIZE + 4095) & !4095;
-            info!("Erasing {} bytes...", total_erase);
-            if let Err(e) = flash.erase(APP_OFFSET, APP_OFFSET + total_erase) {
-                error!("Erase failed: {:?}", e);
-            } else {
-                // Send ACK ONLY AFTER successful erase.
-                // This prevents the downloader from timing out while we are busy erasing.
-                let _ = uart.write(&[0x06]).await;
-
-                let mut write_buf = [0u8; 4096];
-                let mut received = 0;
-                
-                // Real app writing
-                while received < len {
-                    let chunk_len = core::cmp::min(4096, (len - received) as usize);
-                    if uart.read(&mut write_buf[..chunk_len]).await.is_ok() {
-                        if let Err(e) = flash.write(APP_OFFSET + METADATA_SIZE + received, &write_buf[..chunk_len]) {
-                            error!("Flash write failed: {:?}", e);
-                            break;
-                        }
-                        received += chunk_len as u32;
-                        info!("Received {}/{} bytes", received, len);
-                        
-                        // Send ACK for each chunk
-                        let _ = uart.write(&[0x06]).await;
-                    } else {
-                        error!("UART read failed");
-                        break;
-                    }
-                }
+        info!("DFU Mode: awaiting framed protocol handshake...");
+        // dfu::run arms the watchdog itself once the host actually starts
+        // talking, rather than across this idle wait - see its doc comment.
+        dfu::run(&mut uart, &mut flash, &mut watchdog).await;
+        // dfu::run only returns on an unrecoverable transfer error (e.g. a
+        // final CRC mismatch); a successful transfer resets the device.
+    }
 
-                if received == len {
-                    info!("Verifying CRC32...");
-                    if unsafe { verify_flash_crc(APP_BASE + METADATA_SIZE, len, crc_val) } {
-                        info!("CRC OK! Writing metadata...");
-                        let mut metadata = [0u8; 256];
-                        metadata[0..4].copy_from_slice(MAGIC_APPS);
-                        metadata[4..8].copy_from_slice(&len.to_le_bytes());
-                        metadata[8..12].copy_from_slice(&crc_val.to_le_bytes());
-                        
-                        if let Err(e) = flash.write(APP_OFFSET, &metadata) {
-                            error!("Metadata write failed: {:?}", e);
-                        } else {
-                            info!("Update complete! Resetting system...");
-                            // Wait a bit for the message to be sent
-                            for _ in 0..100000 { core::hint::spin_loop(); }
-                            cortex_m::peripheral::SCB::sys_reset();
-                        }
-                    } else {
-                        error!("CRC mismatch! Application might be corrupted.");
-                    }
-                }
-            }
-        }
+    if boot_state == BootState::Trial {
+        info!("Arming trial-boot watchdog before handing off to the app...");
+        watchdog::start(&mut watchdog, watchdog::TRIAL_BOOT_TIMEOUT);
     }
 
     led.set_low();
@@ -168,35 +149,131 @@ async fn main(_spawner: Spawner) {
     }
 }
 
-unsafe fn is_app_healthy(address: u32) -> bool {
-    let magic = unsafe { core::slice::from_raw_parts(address as *const u8, 4) };
-    if magic != MAGIC_APPS {
-        return false;
+/// Drives the boot-state machine to completion: finishes (or resumes) a
+/// pending swap into the active slot, counts trial boots, and rolls back
+/// to the previous slot if the app never confirms itself. Returns the
+/// resulting state so the caller knows whether to arm the trial-boot
+/// watchdog before jumping.
+///
+/// `watchdog_caused_reset` lets a hung `Trial` app roll back immediately
+/// instead of waiting out the full `MAX_TRIAL_BOOTS` counter, and
+/// `trial_confirmed` (the app having written [`watchdog::MAGIC_TRIAL_CONFIRMED`]
+/// before this reset) prevents that same fast path from misreading a
+/// deliberate self-reset as a hang.
+fn resolve_boot_state(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, watchdog_caused_reset: bool, trial_confirmed: bool) -> BootState {
+    let mut state = boot_state::load_state(flash, STATE_OFFSET);
+
+    if state.state == BootState::Trial {
+        if trial_confirmed {
+            info!("App confirmed itself via scratch register");
+            state = BootStatePage::default();
+            boot_state::store_state(flash, STATE_OFFSET, state);
+        } else if watchdog_caused_reset {
+            warn!("Watchdog reset during trial boot (app likely hung), rolling back");
+            state.state = BootState::SwapPending;
+            state.rollback = true;
+            state.backup_done = true; // restoring a backup needs no backup of its own
+            state.copy_progress = 0;
+            boot_state::store_state(flash, STATE_OFFSET, state);
+        } else {
+            state.boot_count += 1;
+            info!("Trial boot #{}", state.boot_count);
+            if state.boot_count > boot_state::MAX_TRIAL_BOOTS {
+                warn!("App never confirmed itself, rolling back to previous slot");
+                state.state = BootState::SwapPending;
+                state.rollback = true;
+                state.backup_done = true;
+                state.copy_progress = 0;
+            }
+            boot_state::store_state(flash, STATE_OFFSET, state);
+        }
     }
 
-    let len = unsafe { *((address + 4) as *const u32) };
-    let expected_crc = unsafe { *((address + 8) as *const u32) };
+    if state.state == BootState::SwapPending {
+        perform_swap(flash, &mut state);
+    }
 
-    info!("App Metadata: Len={}, CRC=0x{:x}", len, expected_crc);
+    state.state
+}
 
-    // Basic SP check within the app first 4 bytes
-    let sp = unsafe { *((address + METADATA_SIZE) as *const u32) };
-    if sp < 0x20000000 || sp > 0x20082000 {
-        return false;
+/// Drives `slot::copy_slot_chunk` to completion, persisting `state.copy_progress`
+/// to the boot-state page after every chunk rather than only once the whole
+/// copy finishes - so a reset mid-copy resumes a chunk back instead of
+/// redoing the entire slot. Returns `false` (without touching `state`'s
+/// other fields) on the first chunk that fails to copy, leaving
+/// `copy_progress` at the last successfully-persisted value for a retry.
+fn copy_slot_resumable(
+    flash: &mut Flash<FLASH, Async, FLASH_SIZE>,
+    state: &mut BootStatePage,
+    src_offset: u32,
+    dst_offset: u32,
+    total: u32,
+) -> bool {
+    while state.copy_progress < total {
+        let (mut src, mut dst) = (Slot::new(&mut *flash, src_offset, SLOT_SIZE), Slot::new(&mut *flash, dst_offset, SLOT_SIZE));
+        match slot::copy_slot_chunk(&mut src, &mut dst, total, state.copy_progress) {
+            Ok(progress) => state.copy_progress = progress,
+            Err(()) => return false,
+        }
+        boot_state::store_state(flash, STATE_OFFSET, *state);
     }
-
-    unsafe { verify_flash_crc(address + METADATA_SIZE, len, expected_crc) }
+    true
 }
 
-unsafe fn verify_flash_crc(address: u32, len: u32, expected: u32) -> bool {
-    let data = unsafe { core::slice::from_raw_parts(address as *const u8, len as usize) };
-    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-    let mut digest = crc.digest();
-    digest.update(data);
-    let calculated = digest.finalize();
-    
-    info!("CRC Check: Calc=0x{:x}, Exp=0x{:x}", calculated, expected);
-    calculated == expected
+fn perform_swap(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, state: &mut BootStatePage) {
+    if !state.rollback && !state.backup_done {
+        info!("Backing up active slot to previous...");
+        match Slot::new(flash, APP_OFFSET, SLOT_SIZE).image_len() {
+            Some(len) => {
+                let total = METADATA_SIZE + len;
+                // Only erase on a fresh copy, not a resume - the destination
+                // already holds a correctly-erased-and-partially-written
+                // image from before the reset that interrupted us.
+                if state.copy_progress == 0 {
+                    if let Err(e) = Slot::new(&mut *flash, PREVIOUS_OFFSET, SLOT_SIZE).erase(slot::round_up_to_sector(total)) {
+                        error!("Erase of previous slot failed: {:?}", e);
+                        return;
+                    }
+                }
+                if !copy_slot_resumable(flash, state, APP_OFFSET, PREVIOUS_OFFSET, total) {
+                    error!("Backup of active slot into previous failed, leaving backup_done unset for retry");
+                    return;
+                }
+            }
+            None => info!("Active slot is empty, nothing to back up"),
+        }
+        state.backup_done = true;
+        state.copy_progress = 0;
+        boot_state::store_state(flash, STATE_OFFSET, *state);
+    }
+
+    let src_offset = if state.rollback { PREVIOUS_OFFSET } else { STAGING_OFFSET };
+    let src_name = if state.rollback { "previous" } else { "staging" };
+    match Slot::new(flash, src_offset, SLOT_SIZE).image_len() {
+        Some(len) => {
+            let total = METADATA_SIZE + len;
+            info!("Copying {} slot into active ({} bytes)...", src_name, total);
+            // Same resume-vs-fresh-copy reasoning as the backup above.
+            if state.copy_progress == 0 {
+                if let Err(e) = Slot::new(&mut *flash, APP_OFFSET, SLOT_SIZE).erase(slot::round_up_to_sector(total)) {
+                    error!("Erase of active slot failed: {:?}", e);
+                    return;
+                }
+            }
+            let copied = copy_slot_resumable(flash, state, src_offset, APP_OFFSET, total);
+            if copied && Slot::new(flash, APP_OFFSET, SLOT_SIZE).is_app_healthy() {
+                state.state = if state.rollback { BootState::BootOk } else { BootState::Trial };
+                state.rollback = false;
+                state.backup_done = false;
+                state.boot_count = 0;
+                state.copy_progress = 0;
+                boot_state::store_state(flash, STATE_OFFSET, *state);
+            } else {
+                error!("Swap into active slot failed or result is unhealthy");
+            }
+        }
+        None => error!("{} slot has no valid image to swap in", src_name),
+    }
 }
 
 unsafe fn jump_to_app(address: u32) -> ! {
@@ -0,0 +1,111 @@
+//! RAM-resident self-flash recovery: reprograms the bootloader region
+//! itself from an image held entirely in RAM, as a last-resort recovery
+//! path when the on-flash bootloader is damaged. Mirrors the "flash from
+//! RAM into NVM" escape hatch other platforms offer as a software
+//! alternative to a hardware debugger.
+//!
+//! An image flashed this way *replaces the bootloader*, so it must itself
+//! retain this same recovery path - otherwise a second bad flash has no
+//! way back.
+
+use defmt::*;
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::peripherals::FLASH;
+
+use crate::{APP_OFFSET, FLASH_SIZE};
+
+/// Must be echoed in the `EnterRecovery` frame before any flash is
+/// touched - this mode is destructive and not something a host should
+/// fall into by accident or by a single corrupted byte.
+pub const CONFIRMATION_TOKEN: u32 = 0xDEAD_C0DE;
+
+/// Bootloader images can't be bigger than the region reserved for them.
+pub const MAX_BOOTLOADER_SIZE: usize = APP_OFFSET as usize;
+
+/// RAM staging buffer for an incoming recovery image. Only ever touched
+/// from the single bootloader task, so the `unsafe` needed to hand out a
+/// `&mut` to this `static mut` is sound in practice.
+static mut RECOVERY_BUFFER: [u8; MAX_BOOTLOADER_SIZE] = [0; MAX_BOOTLOADER_SIZE];
+
+pub fn buffer() -> &'static mut [u8; MAX_BOOTLOADER_SIZE] {
+    unsafe { &mut *core::ptr::addr_of_mut!(RECOVERY_BUFFER) }
+}
+
+/// CRC32 and stack-pointer sanity checks against the RAM buffer, mirroring
+/// `Slot::is_app_healthy` but performed entirely in RAM since the flash
+/// being replaced can't be trusted to read back correctly mid-recovery.
+fn verify(image: &[u8], expected_crc: u32) -> bool {
+    if image.len() < 256 || image.len() > MAX_BOOTLOADER_SIZE {
+        warn!("Recovery image size {} out of range", image.len());
+        return false;
+    }
+    let sp = u32::from_le_bytes(image[0..4].try_into().unwrap());
+    if sp < 0x20000000 || sp > 0x20082000 {
+        warn!("Recovery image has an implausible initial SP: 0x{:x}", sp);
+        return false;
+    }
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let mut digest = crc.digest();
+    digest.update(image);
+    let calculated = digest.finalize();
+    info!("Recovery image CRC: Calc=0x{:x}, Exp=0x{:x}", calculated, expected_crc);
+    calculated == expected_crc
+}
+
+/// Resets the device from RAM once the bootloader region has been
+/// rewritten. Triggers the reset directly through the SCB's AIRCR
+/// register rather than calling `cortex_m::peripheral::SCB::sys_reset()` -
+/// that function's own compiled code lives in ordinary flash `.text`,
+/// which by now holds whatever the new image happens to have at that
+/// address, not a continuation of `cortex-m`'s reset routine. Everything
+/// this touches has to stay RAM-resident (or register-only) right up to
+/// the reset actually landing.
+#[inline(never)]
+#[unsafe(link_section = ".data.recovery_flash")]
+fn reset_into_new_image() -> ! {
+    const AIRCR: *mut u32 = 0xE000_ED0C as *mut u32;
+    const VECTKEY: u32 = 0x05FA_0000;
+    const SYSRESETREQ: u32 = 1 << 2;
+    unsafe {
+        core::arch::asm!("dsb");
+        core::ptr::write_volatile(AIRCR, VECTKEY | SYSRESETREQ);
+        core::arch::asm!("dsb");
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Erases and rewrites the bootloader region (`[0, APP_OFFSET)`) from a
+/// RAM-resident image, then resets. Placed in a RAM link section via
+/// `#[link_section]` so it keeps executing while the erase/write disables
+/// XIP reads of the very flash region it is reprogramming - and, just as
+/// importantly, so it never has to *return* into that region afterwards.
+/// Once `flash.write` lands, `[0, APP_OFFSET)` holds the new image's
+/// bytes, not this bootloader's, so returning into `commit`'s caller
+/// (ordinary flash-resident code) would execute whatever instruction the
+/// new image happens to have there. The only way out from here on is
+/// [`reset_into_new_image`]; this only returns on an erase/write failure,
+/// before any of that has happened.
+#[inline(never)]
+#[unsafe(link_section = ".data.recovery_flash")]
+fn flash_self_from_ram(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, image: &[u8]) -> Result<(), ()> {
+    let total_erase = (image.len() as u32 + 4095) & !4095;
+    flash.erase(0, total_erase).map_err(|_| ())?;
+    flash.write(0, image).map_err(|_| ())?;
+    reset_into_new_image();
+}
+
+/// Verifies and commits a fully-received recovery image. Only returns on
+/// failure - a bad image fails `verify` before anything is touched, and an
+/// erase/write failure fails before the bootloader region is fully
+/// rewritten. Success never returns: [`flash_self_from_ram`] resets the
+/// device itself once the new image is in flash, rather than handing
+/// control back to this function's flash-resident caller.
+pub fn commit(flash: &mut Flash<FLASH, Async, FLASH_SIZE>, image: &[u8], expected_crc: u32) -> Result<(), ()> {
+    if !verify(image, expected_crc) {
+        return Err(());
+    }
+    info!("Recovery image verified, reprogramming bootloader region from RAM...");
+    flash_self_from_ram(flash, image)
+}
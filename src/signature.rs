@@ -0,0 +1,113 @@
+//! Signed-image verification, gated behind the `signed-image` feature.
+//!
+//! CRC32 (see [`crate::slot::Slot::verify_crc`]) only guards against
+//! accidental corruption - it says nothing about *who* produced an image.
+//! When this feature is enabled, callers additionally hash the image with
+//! SHA-256 and check the result against a detached Ed25519 signature, so a
+//! slot is only ever marked swappable if the image was signed by whoever
+//! holds the matching private key.
+//!
+//! Hashing is exposed as an incremental [`Hasher`] rather than a
+//! single-shot function taking `&[u8]`, because a `Slot` streams an image
+//! out of flash through a small fixed buffer (see
+//! `Slot::verify_crc`/`verify_signature`) rather than holding it as one
+//! contiguous slice.
+//!
+//! The public key lives in its own link section rather than as a plain
+//! `const`, so a device can be provisioned with its own key (e.g. via
+//! `picotool` writing just that section) without recompiling the
+//! bootloader itself.
+//!
+//! Builds that don't need this - CRC-only deployments, or early bring-up
+//! before a signing pipeline exists - compile this module out entirely by
+//! disabling the `signed-image` feature; [`crate::slot::Slot::is_app_healthy`]
+//! and [`crate::dfu`]'s end-of-transfer check fall back to CRC32 alone.
+
+use defmt::*;
+use sha2::{Digest, Sha256};
+
+/// SHA-256 digest size.
+pub const HASH_SIZE: usize = 32;
+/// Detached Ed25519 signature size.
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Public key used to verify image signatures. Lives in its own section so
+/// it can be provisioned per-device independently of the rest of the
+/// bootloader image; a device shipped without ever flashing this section
+/// keeps the all-zero placeholder below, which [`verify_signature`]
+/// explicitly rejects via [`LOW_ORDER_KEYS`] before it ever reaches
+/// `VerifyingKey::from_bytes`.
+#[unsafe(link_section = ".rodata.signing_key")]
+#[used]
+pub static PUBLIC_KEY: [u8; 32] = [0u8; 32];
+
+/// Compressed encodings of points of order 1, 2, or 4 on edwards25519,
+/// including both sign-bit variants of each.
+///
+/// Verifying against any of these is a forgery, not a rejection: the
+/// public-key term in the Ed25519 verification equation vanishes for a
+/// key whose order divides the curve's cofactor, so *any* signature
+/// "verifies" against one without the signer knowing any private key.
+/// `VerifyingKey::from_bytes` happily decompresses these - an all-zero
+/// key is a valid low-order curve point, not a decompression failure -
+/// so they have to be rejected here, before decompression is even
+/// attempted.
+const LOW_ORDER_KEYS: [[u8; 32]; 6] = [
+    // y = 0 (order 4). This is PUBLIC_KEY's unprovisioned placeholder.
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80],
+    // y = 1, x = 0 (order 1, the identity point).
+    [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00],
+    [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80],
+    // y = p - 1 (order 2).
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+    ],
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ],
+];
+
+/// Incremental SHA-256 hasher, fed one chunk of image at a time.
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self(Sha256::new())
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.0.update(chunk);
+    }
+
+    pub fn finalize(self) -> [u8; HASH_SIZE] {
+        self.0.finalize().into()
+    }
+}
+
+/// Checks `signature` against `hash` using [`PUBLIC_KEY`]. Returns `false`
+/// for an invalid key section as well as a failed verification - callers
+/// should treat every `false` the same way: refuse to swap the image in.
+pub fn verify_signature(hash: &[u8; HASH_SIZE], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    if LOW_ORDER_KEYS.contains(&PUBLIC_KEY) {
+        error!("Signing public key is a low-order point (unprovisioned or malicious), refusing");
+        return false;
+    }
+
+    let Ok(key) = VerifyingKey::from_bytes(&PUBLIC_KEY) else {
+        error!("Signing public key section is not a valid Ed25519 key");
+        return false;
+    };
+    let sig = Signature::from_bytes(signature);
+    match key.verify(hash, &sig) {
+        Ok(()) => true,
+        Err(_) => {
+            warn!("Image signature verification failed");
+            false
+        }
+    }
+}
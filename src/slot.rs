@@ -0,0 +1,199 @@
+//! Generic flash-region abstraction. `Slot` wraps any `embedded-storage`
+//! `NorFlash`/`ReadNorFlash` implementation together with an address range,
+//! so the active/staging/previous regions could each live on whatever flash
+//! device backs them, without the verification logic caring which.
+//!
+//! This crate only ever instantiates `Slot` over `embassy_rp`'s internal
+//! XIP flash today - there's no driver, feature flag, or config wiring up
+//! an external QSPI/SPI part yet. Being generic over `NorFlash` is what
+//! makes adding one later (e.g. an external chip for the staging slot) a
+//! matter of writing that driver and instantiating `Slot<ThatDriver>`,
+//! not of changing anything in this module.
+
+use defmt::*;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::{ERASE_GRANULARITY, MAGIC_APPS, METADATA_SIZE};
+#[cfg(feature = "signed-image")]
+use crate::signature::{self, HASH_SIZE, SIGNATURE_SIZE};
+
+/// Rounds `n` up to the next multiple of [`ERASE_GRANULARITY`] - NOR flash
+/// can only erase whole sectors, so every erase call in this crate needs
+/// its length rounded up to this before it reaches the flash driver.
+pub(crate) fn round_up_to_sector(n: u32) -> u32 {
+    (n + ERASE_GRANULARITY - 1) & !(ERASE_GRANULARITY - 1)
+}
+
+/// Metadata page layout: `[magic:4][len:4][crc32:4]`, followed - only when
+/// built with `signed-image` - by `[sha256:32][ed25519 sig:64]`. The
+/// trailing bytes are reserved either way, so a CRC-only bootloader and a
+/// signed one agree on where the image itself starts.
+#[cfg(feature = "signed-image")]
+pub(crate) const HASH_OFFSET: u32 = 12;
+#[cfg(feature = "signed-image")]
+pub(crate) const SIGNATURE_OFFSET: u32 = HASH_OFFSET + HASH_SIZE as u32;
+
+/// A fixed-size region of a `NorFlash` device holding a metadata page
+/// (magic + length + CRC32) followed by an application image.
+pub struct Slot<'a, F> {
+    flash: &'a mut F,
+    offset: u32,
+    len: u32,
+}
+
+impl<'a, F> Slot<'a, F> {
+    pub fn new(flash: &'a mut F, offset: u32, len: u32) -> Self {
+        Self { flash, offset, len }
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+}
+
+impl<'a, F: ReadNorFlash> Slot<'a, F> {
+    pub fn read(&mut self, rel_offset: u32, buf: &mut [u8]) -> Result<(), F::Error> {
+        self.flash.read(self.offset + rel_offset, buf)
+    }
+
+    /// Reads the metadata page and returns the declared image length, if
+    /// the magic matches.
+    pub fn image_len(&mut self) -> Option<u32> {
+        let mut header = [0u8; 8];
+        self.read(0, &mut header).ok()?;
+        if &header[0..4] != MAGIC_APPS {
+            return None;
+        }
+        Some(u32::from_le_bytes(header[4..8].try_into().unwrap()))
+    }
+
+    /// Verifies the image's CRC32 against `expected`, streaming it through
+    /// a fixed-size buffer rather than requiring the whole image to be
+    /// memory-mapped - so the same check would work against an off-chip
+    /// SPI flash slot just as well as internal XIP flash, should one ever
+    /// back a `Slot` here.
+    pub fn verify_crc(&mut self, len: u32, expected: u32) -> bool {
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut digest = crc.digest();
+        let mut buf = [0u8; 256];
+        let mut done = 0u32;
+        while done < len {
+            let n = core::cmp::min(buf.len() as u32, len - done) as usize;
+            if self.read(METADATA_SIZE + done, &mut buf[..n]).is_err() {
+                return false;
+            }
+            digest.update(&buf[..n]);
+            done += n as u32;
+        }
+        let calculated = digest.finalize();
+        info!("CRC Check: Calc=0x{:x}, Exp=0x{:x}", calculated, expected);
+        calculated == expected
+    }
+
+    /// Magic + stack-pointer sanity check + full CRC32 verification.
+    pub fn is_app_healthy(&mut self) -> bool {
+        let mut header = [0u8; 12];
+        if self.read(0, &mut header).is_err() {
+            return false;
+        }
+        if &header[0..4] != MAGIC_APPS {
+            return false;
+        }
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let expected_crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        info!("App Metadata: Len={}, CRC=0x{:x}", len, expected_crc);
+
+        let mut sp_buf = [0u8; 4];
+        if self.read(METADATA_SIZE, &mut sp_buf).is_err() {
+            return false;
+        }
+        let sp = u32::from_le_bytes(sp_buf);
+        if sp < 0x20000000 || sp > 0x20082000 {
+            return false;
+        }
+
+        if !self.verify_crc(len, expected_crc) {
+            return false;
+        }
+
+        #[cfg(feature = "signed-image")]
+        if !self.verify_signature(len) {
+            return false;
+        }
+
+        true
+    }
+
+    /// Re-hashes the image and checks the stored signature against it.
+    /// Only compiled in with the `signed-image` feature; see
+    /// [`crate::signature`].
+    #[cfg(feature = "signed-image")]
+    fn verify_signature(&mut self, len: u32) -> bool {
+        let mut hash = [0u8; HASH_SIZE];
+        let mut sig = [0u8; SIGNATURE_SIZE];
+        if self.read(HASH_OFFSET, &mut hash).is_err() || self.read(SIGNATURE_OFFSET, &mut sig).is_err() {
+            return false;
+        }
+
+        let mut hasher = signature::Hasher::new();
+        let mut buf = [0u8; 256];
+        let mut done = 0u32;
+        while done < len {
+            let n = core::cmp::min(buf.len() as u32, len - done) as usize;
+            if self.read(METADATA_SIZE + done, &mut buf[..n]).is_err() {
+                return false;
+            }
+            hasher.update(&buf[..n]);
+            done += n as u32;
+        }
+        let computed = hasher.finalize();
+        if computed != hash {
+            warn!("Image hash mismatch");
+            return false;
+        }
+
+        signature::verify_signature(&hash, &sig)
+    }
+}
+
+impl<'a, F: NorFlash> Slot<'a, F> {
+    pub fn erase(&mut self, up_to: u32) -> Result<(), F::Error> {
+        self.flash.erase(self.offset, self.offset + up_to)
+    }
+
+    pub fn write(&mut self, rel_offset: u32, data: &[u8]) -> Result<(), F::Error> {
+        self.flash.write(self.offset + rel_offset, data)
+    }
+}
+
+/// Chunk size used by [`copy_slot_chunk`] - also the unit the caller
+/// persists `copy_progress` at, so a reset never loses more than one
+/// chunk's worth of copying.
+pub const COPY_CHUNK: u32 = 4096;
+
+/// Copies a single `COPY_CHUNK`-sized (or smaller, for the final chunk)
+/// slice of `src`'s image region into `dst`, starting `progress` bytes in,
+/// and returns the new progress. `src` and `dst` can be different
+/// `NorFlash` implementations, so this is also how a staging slot on
+/// external SPI flash would get swapped into an internal active slot, once
+/// something actually drives `Slot` with one.
+///
+/// Copying one chunk per call rather than the whole `[progress, len)` range
+/// in one go is what lets the caller persist `copy_progress` to the
+/// boot-state page between chunks - see `perform_swap` in `main.rs` - so a
+/// reset mid-copy resumes a chunk back instead of redoing the whole slot.
+pub fn copy_slot_chunk<S, D>(src: &mut Slot<S>, dst: &mut Slot<D>, len: u32, progress: u32) -> Result<u32, ()>
+where
+    S: ReadNorFlash,
+    D: NorFlash,
+{
+    let mut buf = [0u8; COPY_CHUNK as usize];
+    let n = core::cmp::min(COPY_CHUNK, len - progress) as usize;
+    src.read(progress, &mut buf[..n]).map_err(|_| ())?;
+    dst.write(progress, &buf[..n]).map_err(|_| ())?;
+    Ok(progress + n as u32)
+}
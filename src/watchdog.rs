@@ -0,0 +1,56 @@
+//! Watchdog integration.
+//!
+//! The watchdog does two jobs here: it is fed during the bootloader's own
+//! long-running erase/write operations so a hung UART transfer reboots
+//! cleanly instead of wedging forever in `uart.read().await`, and it is
+//! armed with a bounded timeout while a freshly-swapped app runs its first
+//! (`Trial`) boot, so an app that hangs outright - rather than crashing or
+//! resetting itself - still comes back here to roll back instead of
+//! bricking the device for the full `MAX_TRIAL_BOOTS` window.
+//!
+//! A watchdog scratch register (`scratch0`) doubles as a tiny persistent
+//! handoff channel between application firmware and the bootloader, since
+//! it (unlike plain RAM) survives the reset that carries a message across.
+
+use embassy_rp::pac;
+use embassy_rp::watchdog::Watchdog;
+use embassy_time::Duration;
+
+/// Written into the command scratch register by application firmware that
+/// wants the next boot to enter DFU mode directly, skipping the 3-second
+/// 'u' prompt entirely.
+pub const MAGIC_ENTER_DFU: u32 = 0xB00710AD;
+/// Written into the command scratch register by application firmware once
+/// it has confirmed itself good during a `Trial` boot, so a watchdog reset
+/// that follows (e.g. the app resetting itself to apply a setting) isn't
+/// mistaken for a failed trial.
+pub const MAGIC_TRIAL_CONFIRMED: u32 = 0x600DB007;
+
+/// How long a freshly-swapped app gets to feed the watchdog and confirm
+/// itself before we assume it has hung and roll back.
+pub const TRIAL_BOOT_TIMEOUT: Duration = Duration::from_secs(8);
+/// How long the bootloader gives a DFU transfer to make progress before
+/// assuming the host has gone away.
+pub const DFU_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads and clears the command scratch register, so a stale command
+/// can't leak into a later boot it wasn't meant for.
+pub fn take_command() -> u32 {
+    let value = pac::WATCHDOG.scratch0().read();
+    pac::WATCHDOG.scratch0().write(|_| {});
+    value
+}
+
+/// True if the watchdog timer itself forced the most recent reset, as
+/// opposed to a power-on, `SCB::sys_reset()`, or debugger reset.
+pub fn caused_reset() -> bool {
+    pac::WATCHDOG.reason().read().timer()
+}
+
+pub fn start(watchdog: &mut Watchdog, timeout: Duration) {
+    watchdog.start(timeout);
+}
+
+pub fn feed(watchdog: &mut Watchdog) {
+    watchdog.feed();
+}